@@ -1,40 +1,110 @@
-//! Stream protocol implementation
+//! Stream and AEAD protocol implementation
 
 use std::{
-    cmp,
+    cmp, error, fmt,
+    future::Future,
     io,
     marker::Unpin,
     pin::Pin,
     task::{Context, Poll},
 };
 
-use crate::crypto::{new_stream, BoxStreamCipher, CipherType, CryptoMode};
+use crate::crypto::{
+    new_aead_decryptor, new_aead_encryptor, new_stream, BoxAeadDecryptor, BoxAeadEncryptor, BoxStreamCipher,
+    CipherCategory, CipherType, CryptoMode,
+};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures::ready;
 use tokio::prelude::*;
 
 use super::BUFFER_SIZE;
 
-const DUMMY_BUFFER: [u8; BUFFER_SIZE] = [0u8; BUFFER_SIZE];
+/// Errors produced while decrypting data read from the wire
+///
+/// This separates a genuine transport failure (`IoError`) from a tampered or corrupt ciphertext
+/// (`DecryptError`), so relay logic can drop a connection as a likely active-probing attempt
+/// instead of retrying as if it were a network hiccup.
+#[derive(Debug)]
+pub enum ProtocolError {
+    IoError(io::Error),
+    DecryptError,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ProtocolError::IoError(ref err) => write!(f, "{}", err),
+            ProtocolError::DecryptError => write!(f, "decrypt data failed"),
+        }
+    }
+}
+
+impl error::Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(err: io::Error) -> ProtocolError {
+        ProtocolError::IoError(err)
+    }
+}
+
+impl From<ProtocolError> for io::Error {
+    fn from(err: ProtocolError) -> io::Error {
+        match err {
+            ProtocolError::IoError(err) => err,
+            ProtocolError::DecryptError => io::Error::new(io::ErrorKind::Other, ProtocolError::DecryptError),
+        }
+    }
+}
+
+/// Result type returned by the decrypting readers
+pub type ProtocolResult<T> = Result<T, ProtocolError>;
+
+enum ReadingStep {
+    /// Accumulating `cipher_type.iv_size()` bytes of IV read from the wire before the cipher can
+    /// be constructed
+    WaitIv { key: Bytes, iv_buffer: BytesMut },
+    /// IV has been received (or the cipher doesn't need one) and the stream is being decrypted
+    Read {
+        cipher: BoxStreamCipher,
+        buffer: BytesMut,
+        pos: usize,
+        got_final: bool,
+    },
+}
 
 /// Reader wrapper that will decrypt data automatically
+///
+/// The IV is not known up front -- it arrives as the first `cipher_type.iv_size()` bytes on the
+/// wire, so construction only needs the cipher type and key; the cipher itself is built lazily
+/// once those bytes have been read.
 pub struct DecryptedReader {
-    buffer: BytesMut,
-    cipher: BoxStreamCipher,
-    pos: usize,
-    got_final: bool,
+    cipher_type: CipherType,
+    step: Option<ReadingStep>,
     incoming_buffer: Vec<u8>,
 }
 
 impl DecryptedReader {
-    pub fn new(t: CipherType, key: &[u8], iv: &[u8]) -> DecryptedReader {
-        let cipher = new_stream(t, key, iv, CryptoMode::Decrypt);
-        let buffer_size = cipher.buffer_size(&DUMMY_BUFFER);
+    pub fn new(t: CipherType, key: &[u8]) -> DecryptedReader {
+        let key = Bytes::copy_from_slice(key);
+
+        // Ciphers with a zero-length IV have nothing to wait for
+        let step = if t.iv_size() == 0 {
+            ReadingStep::Read {
+                cipher: new_stream(t, &key, &[], CryptoMode::Decrypt),
+                buffer: BytesMut::new(),
+                pos: 0,
+                got_final: false,
+            }
+        } else {
+            ReadingStep::WaitIv {
+                key,
+                iv_buffer: BytesMut::new(),
+            }
+        };
+
         DecryptedReader {
-            buffer: BytesMut::with_capacity(buffer_size),
-            cipher,
-            pos: 0,
-            got_final: false,
+            cipher_type: t,
+            step: Some(step),
             incoming_buffer: vec![0u8; BUFFER_SIZE],
         }
     }
@@ -44,44 +114,150 @@ impl DecryptedReader {
         ctx: &mut Context<'_>,
         r: &mut R,
         dst: &mut [u8],
-    ) -> Poll<io::Result<usize>>
+    ) -> Poll<ProtocolResult<usize>>
     where
         R: AsyncRead + Unpin,
     {
-        while self.pos >= self.buffer.len() {
-            if self.got_final {
-                return Poll::Ready(Ok(0));
-            }
+        loop {
+            match self.step.take().expect("step must always be restored before returning") {
+                ReadingStep::WaitIv { key, mut iv_buffer } => {
+                    let iv_len = self.cipher_type.iv_size();
 
-            let n = ready!(Pin::new(&mut *r).poll_read(ctx, &mut self.incoming_buffer))?;
+                    while iv_buffer.len() < iv_len {
+                        let n = match Pin::new(&mut *r).poll_read(ctx, &mut self.incoming_buffer) {
+                            Poll::Ready(Ok(n)) => n,
+                            Poll::Ready(Err(err)) => {
+                                self.step = Some(ReadingStep::WaitIv { key, iv_buffer });
+                                return Poll::Ready(Err(err.into()));
+                            }
+                            Poll::Pending => {
+                                self.step = Some(ReadingStep::WaitIv { key, iv_buffer });
+                                return Poll::Pending;
+                            }
+                        };
 
-            // Reset pointers
-            self.buffer.clear();
-            self.pos = 0;
+                        if n == 0 {
+                            use std::io::ErrorKind;
+                            self.step = Some(ReadingStep::WaitIv { key, iv_buffer });
+                            return Poll::Ready(Err(io::Error::from(ErrorKind::UnexpectedEof).into()));
+                        }
 
-            if n == 0 {
-                // Finialize block
-                self.buffer.reserve(self.buffer_size(&[]));
-                self.cipher.finalize(&mut self.buffer)?;
-                self.got_final = true;
-            } else {
-                let data = &self.incoming_buffer[..n];
-                // Ensure we have enough space
-                let buffer_len = self.buffer_size(data);
-                self.buffer.reserve(buffer_len);
-                self.cipher.update(data, &mut self.buffer)?;
-            }
-        }
+                        iv_buffer.extend_from_slice(&self.incoming_buffer[..n]);
+                    }
 
-        let remaining_len = self.buffer.len() - self.pos;
-        let n = cmp::min(dst.len(), remaining_len);
-        (&mut dst[..n]).copy_from_slice(&self.buffer[self.pos..self.pos + n]);
-        self.pos += n;
-        Poll::Ready(Ok(n))
-    }
+                    // Bytes read past the IV already belong to the first ciphertext chunk
+                    let leftover = iv_buffer.split_off(iv_len);
+                    let mut cipher = new_stream(self.cipher_type, &key, &iv_buffer, CryptoMode::Decrypt);
 
-    fn buffer_size(&self, data: &[u8]) -> usize {
-        self.cipher.buffer_size(data)
+                    let mut buffer = BytesMut::new();
+                    if !leftover.is_empty() {
+                        buffer.reserve(cipher.buffer_size(&leftover));
+                        if cipher.update(&leftover, &mut buffer).is_err() {
+                            self.step = Some(ReadingStep::Read {
+                                cipher,
+                                buffer,
+                                pos: 0,
+                                got_final: false,
+                            });
+                            return Poll::Ready(Err(ProtocolError::DecryptError));
+                        }
+                    }
+
+                    self.step = Some(ReadingStep::Read {
+                        cipher,
+                        buffer,
+                        pos: 0,
+                        got_final: false,
+                    });
+                }
+                ReadingStep::Read {
+                    mut cipher,
+                    mut buffer,
+                    mut pos,
+                    mut got_final,
+                } => {
+                    while pos >= buffer.len() {
+                        if got_final {
+                            self.step = Some(ReadingStep::Read {
+                                cipher,
+                                buffer,
+                                pos,
+                                got_final,
+                            });
+                            return Poll::Ready(Ok(0));
+                        }
+
+                        let n = match Pin::new(&mut *r).poll_read(ctx, &mut self.incoming_buffer) {
+                            Poll::Ready(Ok(n)) => n,
+                            Poll::Ready(Err(err)) => {
+                                self.step = Some(ReadingStep::Read {
+                                    cipher,
+                                    buffer,
+                                    pos,
+                                    got_final,
+                                });
+                                return Poll::Ready(Err(err.into()));
+                            }
+                            Poll::Pending => {
+                                self.step = Some(ReadingStep::Read {
+                                    cipher,
+                                    buffer,
+                                    pos,
+                                    got_final,
+                                });
+                                return Poll::Pending;
+                            }
+                        };
+
+                        // Reset pointers
+                        buffer.clear();
+                        pos = 0;
+
+                        if n == 0 {
+                            // Finialize block
+                            buffer.reserve(cipher.buffer_size(&[]));
+                            if cipher.finalize(&mut buffer).is_err() {
+                                self.step = Some(ReadingStep::Read {
+                                    cipher,
+                                    buffer,
+                                    pos,
+                                    got_final,
+                                });
+                                return Poll::Ready(Err(ProtocolError::DecryptError));
+                            }
+                            got_final = true;
+                        } else {
+                            let data = &self.incoming_buffer[..n];
+                            // Ensure we have enough space
+                            let buffer_len = cipher.buffer_size(data);
+                            buffer.reserve(buffer_len);
+                            if cipher.update(data, &mut buffer).is_err() {
+                                self.step = Some(ReadingStep::Read {
+                                    cipher,
+                                    buffer,
+                                    pos,
+                                    got_final,
+                                });
+                                return Poll::Ready(Err(ProtocolError::DecryptError));
+                            }
+                        }
+                    }
+
+                    let remaining_len = buffer.len() - pos;
+                    let n = cmp::min(dst.len(), remaining_len);
+                    (&mut dst[..n]).copy_from_slice(&buffer[pos..pos + n]);
+                    pos += n;
+
+                    self.step = Some(ReadingStep::Read {
+                        cipher,
+                        buffer,
+                        pos,
+                        got_final,
+                    });
+                    return Poll::Ready(Ok(n));
+                }
+            }
+        }
     }
 }
 
@@ -95,6 +271,7 @@ pub struct EncryptedWriter {
     cipher: BoxStreamCipher,
     steps: EncryptWriteStep,
     iv: Option<Bytes>,
+    sent_final: bool,
 }
 
 impl EncryptedWriter {
@@ -104,6 +281,7 @@ impl EncryptedWriter {
             cipher: new_stream(t, key, &iv, CryptoMode::Encrypt),
             steps: EncryptWriteStep::Nothing,
             iv: Some(iv),
+            sent_final: false,
         }
     }
 
@@ -119,8 +297,6 @@ impl EncryptedWriter {
     where
         W: AsyncWrite + Unpin,
     {
-        // FIXME: How about finalize?
-
         loop {
             match self.steps {
                 EncryptWriteStep::Nothing => {
@@ -157,11 +333,43 @@ impl EncryptedWriter {
         }
     }
 
+    /// Drains any ciphertext that has been produced but not yet fully written to `w`
+    pub fn poll_flush_pending<W>(&mut self, ctx: &mut Context<'_>, w: &mut W) -> Poll<io::Result<()>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if let EncryptWriteStep::Writing(..) = self.steps {
+            ready!(self.poll_write_all_encrypted(ctx, w, &[]))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Flushes the cipher's final block (if any) and shuts `w` down
+    ///
+    /// Some cipher modes buffer a trailing block that is only produced by `cipher.finalize`;
+    /// without this, those bytes would be silently dropped when the connection closes.
+    pub fn poll_shutdown<W>(&mut self, ctx: &mut Context<'_>, w: &mut W) -> Poll<io::Result<()>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        ready!(self.poll_flush_pending(ctx, w))?;
+
+        if !self.sent_final {
+            let mut buf = BytesMut::new();
+            self.cipher_finalize(&mut buf)?;
+            self.steps = EncryptWriteStep::Writing(buf);
+            self.sent_final = true;
+        }
+
+        ready!(self.poll_flush_pending(ctx, w))?;
+
+        Pin::new(w).poll_shutdown(ctx)
+    }
+
     fn cipher_update<B: BufMut>(&mut self, data: &[u8], buf: &mut B) -> io::Result<()> {
         self.cipher.update(data, buf).map_err(From::from)
     }
 
-    #[allow(dead_code)]
     fn cipher_finalize<B: BufMut>(&mut self, buf: &mut B) -> io::Result<()> {
         self.cipher.finalize(buf).map_err(From::from)
     }
@@ -170,3 +378,718 @@ impl EncryptedWriter {
         self.cipher.buffer_size(data)
     }
 }
+
+/// A `DecryptedReader`/`EncryptedWriter` pair bound to an underlying stream `S`
+///
+/// Unlike the bespoke `poll_read_decrypted`/`poll_write_encrypted` methods, `CryptoStream`
+/// implements `AsyncRead`/`AsyncWrite` directly, so it composes with generic IO code and can be
+/// split into independent read/write halves with `tokio::io::split`.
+pub struct CryptoStream<S> {
+    stream: S,
+    reader: DecryptedReader,
+    writer: EncryptedWriter,
+}
+
+impl<S> CryptoStream<S> {
+    pub fn new(stream: S, t: CipherType, key: &[u8], iv: Bytes) -> CryptoStream<S> {
+        CryptoStream {
+            stream,
+            reader: DecryptedReader::new(t, key),
+            writer: EncryptedWriter::new(t, key, iv),
+        }
+    }
+}
+
+impl<S> AsyncRead for CryptoStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, ctx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        match this.reader.poll_read_decrypted(ctx, &mut this.stream, buf) {
+            Poll::Ready(result) => Poll::Ready(result.map_err(io::Error::from)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S> AsyncWrite for CryptoStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(mut self: Pin<&mut Self>, ctx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        this.writer.poll_write_encrypted(ctx, &mut this.stream, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.writer.poll_flush_pending(ctx, &mut this.stream))?;
+        Pin::new(&mut this.stream).poll_flush(ctx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        this.writer.poll_shutdown(ctx, &mut this.stream)
+    }
+}
+
+/// Payloads larger than this have to be split into multiple chunks by `AeadEncryptedWriter`
+pub const AEAD_MAX_PAYLOAD_SIZE: usize = 0x3FFF;
+
+/// Size in bytes of the (encrypted) chunk length field
+const AEAD_LENGTH_SIZE: usize = 2;
+
+enum AeadReadingStep {
+    /// Waiting for `key_len` bytes of salt to arrive before the session subkey can be derived
+    WaitSalt { key: Bytes },
+    /// Waiting for the encrypted length field (2 bytes + tag)
+    ReadLength { cipher: BoxAeadDecryptor },
+    /// Waiting for `length` bytes of payload (+ tag) named by the previous length field
+    ReadData { cipher: BoxAeadDecryptor, length: usize },
+}
+
+/// Reader wrapper that will decrypt data automatically, using the shadowsocks AEAD chunked
+/// protocol
+///
+/// ```plain
+/// [salt][encrypted payload length][length tag][encrypted payload][payload tag]
+/// ```
+pub struct AeadDecryptedReader {
+    buffer: BytesMut,
+    pos: usize,
+    got_final: bool,
+    incoming: BytesMut,
+    step: Option<AeadReadingStep>,
+    cipher_type: CipherType,
+}
+
+impl AeadDecryptedReader {
+    pub fn new(t: CipherType, key: &[u8]) -> AeadDecryptedReader {
+        AeadDecryptedReader {
+            buffer: BytesMut::new(),
+            pos: 0,
+            got_final: false,
+            incoming: BytesMut::new(),
+            step: Some(AeadReadingStep::WaitSalt {
+                key: Bytes::copy_from_slice(key),
+            }),
+            cipher_type: t,
+        }
+    }
+
+    pub fn poll_read_decrypted<R>(
+        &mut self,
+        ctx: &mut Context<'_>,
+        r: &mut R,
+        dst: &mut [u8],
+    ) -> Poll<ProtocolResult<usize>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        while self.pos >= self.buffer.len() {
+            if self.got_final {
+                return Poll::Ready(Ok(0));
+            }
+
+            ready!(self.poll_read_chunk(ctx, r))?;
+        }
+
+        let remaining_len = self.buffer.len() - self.pos;
+        let n = cmp::min(dst.len(), remaining_len);
+        (&mut dst[..n]).copy_from_slice(&self.buffer[self.pos..self.pos + n]);
+        self.pos += n;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_read_chunk<R>(&mut self, ctx: &mut Context<'_>, r: &mut R) -> Poll<ProtocolResult<()>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        loop {
+            match self.step.take().expect("step must always be restored before returning") {
+                AeadReadingStep::WaitSalt { key } => {
+                    let salt_len = self.cipher_type.key_size();
+
+                    match self.poll_fill_incoming(ctx, r, salt_len, true) {
+                        Poll::Ready(Ok(true)) => {}
+                        Poll::Ready(Ok(false)) => {
+                            self.got_final = true;
+                            self.step = Some(AeadReadingStep::WaitSalt { key });
+                            return Poll::Ready(Ok(()));
+                        }
+                        Poll::Ready(Err(err)) => {
+                            self.step = Some(AeadReadingStep::WaitSalt { key });
+                            return Poll::Ready(Err(err.into()));
+                        }
+                        Poll::Pending => {
+                            self.step = Some(AeadReadingStep::WaitSalt { key });
+                            return Poll::Pending;
+                        }
+                    }
+
+                    let salt = self.incoming.split_to(salt_len);
+                    let cipher = new_aead_decryptor(self.cipher_type, &key, &salt);
+                    self.step = Some(AeadReadingStep::ReadLength { cipher });
+                }
+                AeadReadingStep::ReadLength { mut cipher } => {
+                    let tag_size = self.cipher_type.tag_size();
+
+                    match self.poll_fill_incoming(ctx, r, AEAD_LENGTH_SIZE + tag_size, true) {
+                        Poll::Ready(Ok(true)) => {}
+                        Poll::Ready(Ok(false)) => {
+                            self.got_final = true;
+                            self.step = Some(AeadReadingStep::ReadLength { cipher });
+                            return Poll::Ready(Ok(()));
+                        }
+                        Poll::Ready(Err(err)) => {
+                            self.step = Some(AeadReadingStep::ReadLength { cipher });
+                            return Poll::Ready(Err(err.into()));
+                        }
+                        Poll::Pending => {
+                            self.step = Some(AeadReadingStep::ReadLength { cipher });
+                            return Poll::Pending;
+                        }
+                    }
+
+                    let chunk = self.incoming.split_to(AEAD_LENGTH_SIZE + tag_size);
+                    let mut len_buf = [0u8; AEAD_LENGTH_SIZE];
+                    if cipher.decrypt(&chunk, &mut len_buf).is_err() {
+                        self.step = Some(AeadReadingStep::ReadLength { cipher });
+                        return Poll::Ready(Err(ProtocolError::DecryptError));
+                    }
+
+                    let length = u16::from_be_bytes(len_buf) as usize;
+                    if length > AEAD_MAX_PAYLOAD_SIZE {
+                        self.step = Some(AeadReadingStep::ReadLength { cipher });
+                        return Poll::Ready(Err(ProtocolError::DecryptError));
+                    }
+                    self.step = Some(AeadReadingStep::ReadData { cipher, length });
+                }
+                AeadReadingStep::ReadData { mut cipher, length } => {
+                    let tag_size = self.cipher_type.tag_size();
+
+                    match self.poll_fill_incoming(ctx, r, length + tag_size, false) {
+                        Poll::Ready(Ok(true)) => {}
+                        Poll::Ready(Ok(false)) => {
+                            unreachable!("poll_fill_incoming with allow_eof = false never reports clean eof")
+                        }
+                        Poll::Ready(Err(err)) => {
+                            self.step = Some(AeadReadingStep::ReadData { cipher, length });
+                            return Poll::Ready(Err(err.into()));
+                        }
+                        Poll::Pending => {
+                            self.step = Some(AeadReadingStep::ReadData { cipher, length });
+                            return Poll::Pending;
+                        }
+                    }
+
+                    let chunk = self.incoming.split_to(length + tag_size);
+
+                    self.buffer.clear();
+                    self.buffer.resize(length, 0);
+                    self.pos = 0;
+                    if cipher.decrypt(&chunk, &mut self.buffer).is_err() {
+                        self.step = Some(AeadReadingStep::ReadLength { cipher });
+                        return Poll::Ready(Err(ProtocolError::DecryptError));
+                    }
+
+                    self.step = Some(AeadReadingStep::ReadLength { cipher });
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+
+    /// Ensures `self.incoming` holds at least `size` bytes, reading more from `r` as necessary.
+    ///
+    /// Returns `Ok(true)` once enough bytes are buffered. Returns `Ok(false)` if `allow_eof` is
+    /// set and the underlying stream is closed before any byte of the current record arrived,
+    /// which is the only point at which a clean shutdown is legal.
+    fn poll_fill_incoming<R>(
+        &mut self,
+        ctx: &mut Context<'_>,
+        r: &mut R,
+        size: usize,
+        allow_eof: bool,
+    ) -> Poll<io::Result<bool>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut tmp = [0u8; BUFFER_SIZE];
+        while self.incoming.len() < size {
+            let had_bytes = !self.incoming.is_empty();
+            let n = ready!(Pin::new(&mut *r).poll_read(ctx, &mut tmp))?;
+            if n == 0 {
+                if allow_eof && !had_bytes {
+                    return Poll::Ready(Ok(false));
+                }
+                use std::io::ErrorKind;
+                return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+            }
+            self.incoming.extend_from_slice(&tmp[..n]);
+        }
+        Poll::Ready(Ok(true))
+    }
+}
+
+enum AeadEncryptWriteStep {
+    Nothing,
+    Writing(BytesMut),
+}
+
+/// Writer wrapper that will encrypt data automatically, using the shadowsocks AEAD chunked
+/// protocol
+pub struct AeadEncryptedWriter {
+    cipher: BoxAeadEncryptor,
+    steps: AeadEncryptWriteStep,
+    salt: Option<Bytes>,
+    tag_size: usize,
+}
+
+impl AeadEncryptedWriter {
+    /// Creates a new AeadEncryptedWriter, `salt` is the randomly generated per-connection salt
+    /// that will be sent as a preamble ahead of the first chunk
+    pub fn new(t: CipherType, key: &[u8], salt: Bytes) -> AeadEncryptedWriter {
+        AeadEncryptedWriter {
+            cipher: new_aead_encryptor(t, key, &salt),
+            steps: AeadEncryptWriteStep::Nothing,
+            salt: Some(salt),
+            tag_size: t.tag_size(),
+        }
+    }
+
+    /// Encrypts and writes at most one `AEAD_MAX_PAYLOAD_SIZE`-sized chunk of `data`, returning
+    /// the number of plaintext bytes consumed. Callers with more than that much data must call
+    /// this again with the remainder.
+    pub fn poll_write_encrypted<W>(&mut self, ctx: &mut Context<'_>, w: &mut W, data: &[u8]) -> Poll<io::Result<usize>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let data = if data.len() > AEAD_MAX_PAYLOAD_SIZE {
+            &data[..AEAD_MAX_PAYLOAD_SIZE]
+        } else {
+            data
+        };
+
+        ready!(self.poll_write_all_encrypted(ctx, w, data))?;
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_write_all_encrypted<W>(&mut self, ctx: &mut Context<'_>, w: &mut W, data: &[u8]) -> Poll<io::Result<()>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        loop {
+            match self.steps {
+                AeadEncryptWriteStep::Nothing => {
+                    let salt_len = self.salt.as_ref().map_or(0, Bytes::len);
+                    let mut buf = BytesMut::with_capacity(
+                        salt_len + AEAD_LENGTH_SIZE + self.tag_size + data.len() + self.tag_size,
+                    );
+
+                    if let Some(salt) = self.salt.take() {
+                        buf.extend(salt);
+                    }
+
+                    // `BoxAeadEncryptor::encrypt` takes a fixed-size output slice (mirroring
+                    // `BoxAeadDecryptor::decrypt`), so encrypt each record into its own
+                    // correctly-sized scratch buffer before appending it to `buf`
+                    let len_buf = (data.len() as u16).to_be_bytes();
+                    let mut len_chunk = vec![0u8; AEAD_LENGTH_SIZE + self.tag_size];
+                    self.cipher.encrypt(&len_buf, &mut len_chunk)?;
+                    buf.put_slice(&len_chunk);
+
+                    let mut data_chunk = vec![0u8; data.len() + self.tag_size];
+                    self.cipher.encrypt(data, &mut data_chunk)?;
+                    buf.put_slice(&data_chunk);
+
+                    self.steps = AeadEncryptWriteStep::Writing(buf);
+                }
+                AeadEncryptWriteStep::Writing(ref mut buf) => {
+                    while buf.remaining() > 0 {
+                        let n = ready!(Pin::new(&mut *w).poll_write_buf(ctx, buf))?;
+                        if n == 0 {
+                            use std::io::ErrorKind;
+                            return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+                        }
+                    }
+
+                    self.steps = AeadEncryptWriteStep::Nothing;
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+}
+
+/// Calculates a generous buffer size for each direction's `CopyBuffer` when relaying between
+/// `plain` and a `crypto` stream wrapping cipher `t`
+///
+/// `crypto`'s `AsyncRead`/`AsyncWrite` impl already strips the wire framing -- the buffer here
+/// only ever holds plaintext, never raw AEAD records. For AEAD ciphers it's sized to one whole
+/// chunk's plaintext capacity plus its salt/length/tag overhead, so a single poll can typically
+/// drain or fill an entire decrypted chunk instead of looping across several; stream ciphers have
+/// no chunk boundary to size around, so the fixed `BUFFER_SIZE` is used as-is. The same size is
+/// applied to both directions for simplicity, even though only the crypto-to-plain direction
+/// (which drains `crypto`'s decrypted output) benefits from the AEAD sizing
+fn recommended_buffer_size(t: CipherType) -> usize {
+    match t.category() {
+        CipherCategory::Stream => BUFFER_SIZE,
+        CipherCategory::Aead => {
+            let tag_size = t.tag_size();
+            // The very first record is preceded by the per-connection salt
+            t.key_size() + AEAD_LENGTH_SIZE + tag_size + AEAD_MAX_PAYLOAD_SIZE + tag_size
+        }
+    }
+}
+
+/// Reusable copy buffer, pumping bytes from a reader to a writer a `poll` at a time
+struct CopyBuffer {
+    buf: Box<[u8]>,
+    pos: usize,
+    cap: usize,
+    amt: u64,
+}
+
+impl CopyBuffer {
+    fn new(size: usize) -> CopyBuffer {
+        CopyBuffer {
+            buf: vec![0u8; size].into_boxed_slice(),
+            pos: 0,
+            cap: 0,
+            amt: 0,
+        }
+    }
+
+    fn poll_copy<R, W>(
+        &mut self,
+        ctx: &mut Context<'_>,
+        mut r: Pin<&mut R>,
+        mut w: Pin<&mut W>,
+    ) -> Poll<io::Result<u64>>
+    where
+        R: AsyncRead + ?Sized,
+        W: AsyncWrite + ?Sized,
+    {
+        loop {
+            if self.pos == self.cap {
+                let n = ready!(r.as_mut().poll_read(ctx, &mut self.buf))?;
+                if n == 0 {
+                    ready!(w.as_mut().poll_flush(ctx))?;
+                    return Poll::Ready(Ok(self.amt));
+                }
+                self.pos = 0;
+                self.cap = n;
+            }
+
+            while self.pos < self.cap {
+                let n = ready!(w.as_mut().poll_write(ctx, &self.buf[self.pos..self.cap]))?;
+                if n == 0 {
+                    use std::io::ErrorKind;
+                    return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+                }
+                self.pos += n;
+                self.amt += n as u64;
+            }
+        }
+    }
+}
+
+enum TransferState {
+    Running(CopyBuffer),
+    ShuttingDown(u64),
+    Done(u64),
+}
+
+fn transfer_one_direction<R, W>(
+    ctx: &mut Context<'_>,
+    state: &mut TransferState,
+    r: &mut R,
+    w: &mut W,
+) -> Poll<io::Result<u64>>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut r = Pin::new(r);
+    let mut w = Pin::new(w);
+
+    loop {
+        match state {
+            TransferState::Running(buf) => {
+                let amt = ready!(buf.poll_copy(ctx, r.as_mut(), w.as_mut()))?;
+                *state = TransferState::ShuttingDown(amt);
+            }
+            TransferState::ShuttingDown(amt) => {
+                ready!(w.as_mut().poll_shutdown(ctx))?;
+                *state = TransferState::Done(*amt);
+            }
+            TransferState::Done(amt) => return Poll::Ready(Ok(*amt)),
+        }
+    }
+}
+
+/// Future returned by [`copy_bidirectional`]
+pub struct CopyBidirectional<'a, P: ?Sized, C: ?Sized> {
+    plain: &'a mut P,
+    crypto: &'a mut C,
+    plain_to_crypto: TransferState,
+    crypto_to_plain: TransferState,
+}
+
+impl<'a, P, C> Future for CopyBidirectional<'a, P, C>
+where
+    P: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    C: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    type Output = io::Result<(u64, u64)>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<(u64, u64)>> {
+        let this = Pin::into_inner(self);
+
+        let plain_to_crypto = match transfer_one_direction(ctx, &mut this.plain_to_crypto, this.plain, this.crypto) {
+            Poll::Ready(Ok(amt)) => Some(amt),
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => None,
+        };
+
+        let crypto_to_plain = match transfer_one_direction(ctx, &mut this.crypto_to_plain, this.crypto, this.plain) {
+            Poll::Ready(Ok(amt)) => Some(amt),
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => None,
+        };
+
+        match (plain_to_crypto, crypto_to_plain) {
+            (Some(a), Some(b)) => Poll::Ready(Ok((a, b))),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+/// Copies data in both directions between `plain` and `crypto` until both sides reach EOF and
+/// have flushed, sizing each direction's buffer so that it can hold one complete encrypted
+/// record of `cipher_type` without a short read
+pub fn copy_bidirectional<'a, P, C>(
+    plain: &'a mut P,
+    crypto: &'a mut C,
+    cipher_type: CipherType,
+) -> CopyBidirectional<'a, P, C>
+where
+    P: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    C: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let buffer_size = recommended_buffer_size(cipher_type);
+
+    CopyBidirectional {
+        plain,
+        crypto,
+        plain_to_crypto: TransferState::Running(CopyBuffer::new(buffer_size)),
+        crypto_to_plain: TransferState::Running(CopyBuffer::new(buffer_size)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An in-memory duplex: writes append to `buf`, reads are served from the front of it. Good
+    /// enough to drive a reader/writer pair through a full poll loop without a real socket.
+    struct MemoryDuplex {
+        buf: Vec<u8>,
+        pos: usize,
+    }
+
+    impl MemoryDuplex {
+        fn new() -> MemoryDuplex {
+            MemoryDuplex {
+                buf: Vec::new(),
+                pos: 0,
+            }
+        }
+
+        fn rewind(&mut self) {
+            self.pos = 0;
+        }
+    }
+
+    impl AsyncRead for MemoryDuplex {
+        fn poll_read(mut self: Pin<&mut Self>, _ctx: &mut Context<'_>, dst: &mut [u8]) -> Poll<io::Result<usize>> {
+            let remaining = &self.buf[self.pos..];
+            let n = cmp::min(dst.len(), remaining.len());
+            dst[..n].copy_from_slice(&remaining[..n]);
+            self.pos += n;
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    impl AsyncWrite for MemoryDuplex {
+        fn poll_write(mut self: Pin<&mut Self>, _ctx: &mut Context<'_>, src: &[u8]) -> Poll<io::Result<usize>> {
+            self.buf.extend_from_slice(src);
+            Poll::Ready(Ok(src.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Wraps a `MemoryDuplex`, forcing the very first `poll_read` to report `Pending` so tests
+    /// can exercise a reader's behavior across a genuine suspend/resume instead of always
+    /// completing synchronously.
+    struct SlowDuplex {
+        inner: MemoryDuplex,
+        pending_once: bool,
+    }
+
+    impl AsyncRead for SlowDuplex {
+        fn poll_read(mut self: Pin<&mut Self>, ctx: &mut Context<'_>, dst: &mut [u8]) -> Poll<io::Result<usize>> {
+            if !self.pending_once {
+                self.pending_once = true;
+                return Poll::Pending;
+            }
+            Pin::new(&mut self.inner).poll_read(ctx, dst)
+        }
+    }
+
+    fn test_context() -> Context<'static> {
+        fn clone(_: *const ()) -> std::task::RawWaker {
+            std::task::RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        fn no_op(_: *const ()) {}
+
+        static VTABLE: std::task::RawWakerVTable = std::task::RawWakerVTable::new(clone, no_op, no_op, no_op);
+        let raw = std::task::RawWaker::new(std::ptr::null(), &VTABLE);
+        let waker = unsafe { std::task::Waker::from_raw(raw) };
+        Context::from_waker(Box::leak(Box::new(waker)))
+    }
+
+    #[test]
+    fn encrypted_writer_shutdown_delivers_finalized_bytes() {
+        let t = CipherType::from_name("aes-128-ctr").unwrap();
+        let key = vec![0u8; t.key_size()];
+        let iv: Bytes = vec![0u8; t.iv_size()].into();
+        let plaintext = b"hello, shadowsocks";
+
+        // Compute what `cipher.finalize` itself produces for this key/iv/plaintext, independent
+        // of `poll_shutdown`'s wiring, and check that the written stream ends with exactly those
+        // bytes. If this cipher's finalize produces no bytes, that check holds trivially and the
+        // round trip below is what exercises the written stream.
+        let mut reference_cipher = new_stream(t, &key, &iv, CryptoMode::Encrypt);
+        let mut reference_ciphertext = BytesMut::new();
+        reference_cipher.update(plaintext, &mut reference_ciphertext).unwrap();
+        let mut expected_final = BytesMut::new();
+        reference_cipher.finalize(&mut expected_final).unwrap();
+
+        let mut ctx = test_context();
+        let mut transport = MemoryDuplex::new();
+        let mut writer = EncryptedWriter::new(t, &key, iv);
+
+        match writer.poll_write_encrypted(&mut ctx, &mut transport, plaintext) {
+            Poll::Ready(Ok(n)) => assert_eq!(n, plaintext.len()),
+            other => panic!("write did not complete synchronously: {:?}", other),
+        }
+
+        match writer.poll_shutdown(&mut ctx, &mut transport) {
+            Poll::Ready(Ok(())) => {}
+            other => panic!("shutdown did not complete synchronously: {:?}", other),
+        }
+
+        let written = &transport.buf[transport.buf.len() - expected_final.len()..];
+        assert_eq!(written, &expected_final[..]);
+
+        // The full stream -- ciphertext plus finalized trailing bytes -- must still decrypt back
+        // to the original plaintext.
+        transport.rewind();
+
+        let mut reader = DecryptedReader::new(t, &key);
+        let mut out = vec![0u8; plaintext.len()];
+        let mut read = 0;
+        while read < out.len() {
+            match reader.poll_read_decrypted(&mut ctx, &mut transport, &mut out[read..]) {
+                Poll::Ready(Ok(0)) => panic!("unexpected eof before all plaintext was read back"),
+                Poll::Ready(Ok(n)) => read += n,
+                other => panic!("read did not complete synchronously: {:?}", other),
+            }
+        }
+
+        assert_eq!(&out[..], &plaintext[..]);
+    }
+
+    #[test]
+    fn aead_reader_survives_pending_without_losing_step() {
+        let t = CipherType::from_name("aes-128-gcm").unwrap();
+        let key = vec![0u8; t.key_size()];
+        let salt: Bytes = vec![0u8; t.key_size()].into();
+
+        let mut ctx = test_context();
+        let mut transport = MemoryDuplex::new();
+
+        let mut writer = AeadEncryptedWriter::new(t, &key, salt);
+        let plaintext = b"partial reads must not panic";
+        match writer.poll_write_encrypted(&mut ctx, &mut transport, plaintext) {
+            Poll::Ready(Ok(n)) => assert_eq!(n, plaintext.len()),
+            other => panic!("write did not complete synchronously: {:?}", other),
+        }
+        transport.rewind();
+
+        let mut slow = SlowDuplex {
+            inner: transport,
+            pending_once: false,
+        };
+        let mut reader = AeadDecryptedReader::new(t, &key);
+        let mut out = vec![0u8; plaintext.len()];
+
+        // The forced `Pending` must surface without panicking -- this is exactly the case where
+        // `self.step` needs to have been restored before returning.
+        match reader.poll_read_decrypted(&mut ctx, &mut slow, &mut out) {
+            Poll::Pending => {}
+            other => panic!("expected the forced Pending to surface, got {:?}", other),
+        }
+
+        // A second poll (simulating a wake) must pick up exactly where it left off
+        let mut read = 0;
+        while read < out.len() {
+            match reader.poll_read_decrypted(&mut ctx, &mut slow, &mut out[read..]) {
+                Poll::Ready(Ok(0)) => panic!("unexpected eof before all plaintext was read back"),
+                Poll::Ready(Ok(n)) => read += n,
+                other => panic!("read did not complete synchronously: {:?}", other),
+            }
+        }
+
+        assert_eq!(&out[..], &plaintext[..]);
+    }
+
+    #[test]
+    fn aead_reader_rejects_oversized_length() {
+        let t = CipherType::from_name("aes-128-gcm").unwrap();
+        let key = vec![0u8; t.key_size()];
+        let salt: Bytes = vec![0u8; t.key_size()].into();
+
+        let mut ctx = test_context();
+        let mut transport = MemoryDuplex::new();
+
+        // Craft a length record claiming an oversized payload -- a malicious peer could produce
+        // this even though our own writer never would, since `poll_write_encrypted` caps `data`
+        // to `AEAD_MAX_PAYLOAD_SIZE` before it ever reaches the cipher.
+        let mut writer = AeadEncryptedWriter::new(t, &key, salt.clone());
+        let oversized = (AEAD_MAX_PAYLOAD_SIZE as u16 + 1).to_be_bytes();
+        let mut len_chunk = vec![0u8; AEAD_LENGTH_SIZE + t.tag_size()];
+        writer.cipher.encrypt(&oversized, &mut len_chunk).unwrap();
+
+        transport.buf.extend_from_slice(&salt);
+        transport.buf.extend_from_slice(&len_chunk);
+        transport.rewind();
+
+        let mut reader = AeadDecryptedReader::new(t, &key);
+        let mut out = [0u8; 1];
+        match reader.poll_read_decrypted(&mut ctx, &mut transport, &mut out) {
+            Poll::Ready(Err(ProtocolError::DecryptError)) => {}
+            other => panic!("expected DecryptError for an oversized length, got {:?}", other),
+        }
+    }
+}